@@ -0,0 +1,265 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+use tokio::macros::support::{Pin, Poll};
+use crossbeam_queue::SegQueue;
+
+struct KeyedInner<T: 'static + Default + Send> {
+    objects: SegQueue<T>,
+    // See the matching comment on crate::PoolInner::pending: a single
+    // atomic waker slot loses wakeups when more than one task parks on
+    // the same key, so each key keeps a real queue of waiters instead.
+    // Entries are removed by identity from `KeyedPoolFuture`'s `Drop` impl
+    // when a waiter is abandoned before resolving, so the queue doesn't
+    // accumulate dead entries behind it.
+    pending: Mutex<VecDeque<Waker>>,
+}
+
+type Factory<K, T> = Box<dyn FnMut(&K) -> T + Send>;
+
+/// A [`Pool`](crate::Pool)-like pool that keeps a separate free-list and
+/// waiter queue per key, for resources that aren't interchangeable across keys
+/// (e.g. connections segmented by endpoint). Objects are constructed
+/// lazily the first time a key is seen, via `factory`.
+pub struct KeyedPool<K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> {
+    pools: Mutex<HashMap<K, Arc<KeyedInner<T>>>>,
+    factory: Mutex<Factory<K, T>>,
+}
+
+pub struct KeyedPoolFuture<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> {
+    key: K,
+    inner: Arc<KeyedInner<T>>,
+    in_pool: &'a KeyedPool<K, T>,
+    /// The waker this future last pushed onto `KeyedInner::pending`, if
+    /// any, kept so `Drop` can remove that exact entry if the future is
+    /// abandoned without ever getting polled to completion.
+    parked: Option<Waker>,
+}
+
+pub struct KeyedPoolGuard<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> {
+    key: K,
+    content: T,
+    in_pool: &'a KeyedPool<K, T>,
+}
+
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> std::ops::Deref for KeyedPoolGuard<'a, K, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.content
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> std::ops::DerefMut for KeyedPoolGuard<'a, K, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.content
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> Drop for KeyedPoolGuard<'a, K, T> {
+    fn drop(&mut self) {
+        let content = mem::take(&mut self.content);
+        let inner = self.in_pool.inner_for(&self.key);
+        inner.objects.push(content);
+        let waker = inner.pending.lock().unwrap().pop_front();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+// None of this future's fields are self-referential, so moving it around
+// after polling is always sound; this lets `poll` use `Pin::get_mut`
+// without requiring callers to prove `K`/`T: Unpin`.
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> Unpin for KeyedPoolFuture<'a, K, T> {}
+
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> Drop for KeyedPoolFuture<'a, K, T> {
+    fn drop(&mut self) {
+        if let Some(waker) = self.parked.take() {
+            self.inner
+                .pending
+                .lock()
+                .unwrap()
+                .retain(|pending| !pending.will_wake(&waker));
+        }
+    }
+}
+
+impl<'a, K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> Future for KeyedPoolFuture<'a, K, T> {
+    type Output = KeyedPoolGuard<'a, K, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(content) = this.inner.objects.pop() {
+            return Poll::Ready(KeyedPoolGuard {
+                key: this.key.clone(),
+                content,
+                in_pool: this.in_pool,
+            });
+        }
+        this.parked = Some(cx.waker().clone());
+        this.inner.pending.lock().unwrap().push_back(cx.waker().clone());
+        // An object may have been returned between the pop above and the
+        // register, in which case the waker that pushed it never saw us.
+        // Check again now that we're registered to avoid missing it.
+        if let Some(content) = this.inner.objects.pop() {
+            return Poll::Ready(KeyedPoolGuard {
+                key: this.key.clone(),
+                content,
+                in_pool: this.in_pool,
+            });
+        }
+        Poll::Pending
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send + 'static, T: 'static + Default + Send> KeyedPool<K, T> {
+    pub fn new(factory: impl FnMut(&K) -> T + Send + 'static) -> Self {
+        Self {
+            pools: Mutex::new(HashMap::new()),
+            factory: Mutex::new(Box::new(factory)),
+        }
+    }
+
+    fn inner_for(&self, key: &K) -> Arc<KeyedInner<T>> {
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(inner) = pools.get(key) {
+            return inner.clone();
+        }
+        let content = (self.factory.lock().unwrap())(key);
+        let inner = Arc::new(KeyedInner {
+            objects: SegQueue::new(),
+            pending: Mutex::new(VecDeque::new()),
+        });
+        inner.objects.push(content);
+        pools.insert(key.clone(), inner.clone());
+        inner
+    }
+
+    pub fn take(&self, key: K) -> KeyedPoolFuture<K, T> {
+        let inner = self.inner_for(&key);
+        KeyedPoolFuture {
+            key,
+            inner,
+            in_pool: self,
+            parked: None,
+        }
+    }
+
+    pub fn try_take(&self, key: K) -> Option<KeyedPoolGuard<K, T>> {
+        let inner = self.inner_for(&key);
+        inner.objects.pop().map(|content| KeyedPoolGuard {
+            key,
+            content,
+            in_pool: self,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distinct_keys_do_not_block_each_other() {
+        let pool = KeyedPool::new(|_key: &&str| 0);
+        let _a = pool.try_take("a").unwrap();
+        // "b" has never been seen before, so it's constructed lazily and
+        // should be available immediately even though "a" is checked out.
+        assert!(pool.try_take("b").is_some());
+    }
+
+    #[test]
+    fn concurrent_waiters_on_the_same_key_are_all_woken() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::task::{Context, Wake, Waker};
+
+        struct FlagWaker(AtomicBool);
+        impl Wake for FlagWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let pool = KeyedPool::new(|_key: &&str| 0);
+        let guard = pool.try_take("a").unwrap();
+
+        let mut first = Box::pin(pool.take("a"));
+        let mut second = Box::pin(pool.take("a"));
+        let flag1 = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let flag2 = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker1 = Waker::from(flag1.clone());
+        let waker2 = Waker::from(flag2.clone());
+        let mut cx1 = Context::from_waker(&waker1);
+        let mut cx2 = Context::from_waker(&waker2);
+
+        assert!(first.as_mut().poll(&mut cx1).is_pending());
+        assert!(second.as_mut().poll(&mut cx2).is_pending());
+
+        drop(guard);
+        assert!(
+            flag1.0.load(Ordering::SeqCst) || flag2.0.load(Ordering::SeqCst),
+            "neither waiter was woken when the object was returned"
+        );
+        let woken_first = flag1.0.load(Ordering::SeqCst);
+        if woken_first {
+            assert!(first.as_mut().poll(&mut cx1).is_ready(), "first waiter timed out — lost wakeup");
+        } else {
+            assert!(second.as_mut().poll(&mut cx2).is_ready(), "second waiter timed out — lost wakeup");
+        }
+
+        // The winner's guard was dropped at the end of that statement,
+        // which must hand the object on to the other waiter.
+        if woken_first {
+            assert!(flag2.0.load(Ordering::SeqCst), "second waiter was never woken — lost wakeup");
+            assert!(second.as_mut().poll(&mut cx2).is_ready(), "second waiter timed out — lost wakeup");
+        } else {
+            assert!(flag1.0.load(Ordering::SeqCst), "first waiter was never woken — lost wakeup");
+            assert!(first.as_mut().poll(&mut cx1).is_ready(), "first waiter timed out — lost wakeup");
+        }
+    }
+
+    #[test]
+    fn abandoning_a_waiter_does_not_block_the_next_one_on_the_same_key() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::task::{Context, Wake, Waker};
+
+        struct FlagWaker(AtomicBool);
+        impl Wake for FlagWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let pool = KeyedPool::new(|_key: &&str| 0);
+        let guard = pool.try_take("a").unwrap();
+
+        let flag_a = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker_a = Waker::from(flag_a.clone());
+        let mut cx_a = Context::from_waker(&waker_a);
+        let mut first = Box::pin(pool.take("a"));
+        assert!(first.as_mut().poll(&mut cx_a).is_pending());
+
+        // Abandon the first waiter before it resolves, the way
+        // `tokio::time::timeout` or a losing `select!` branch would. Its
+        // `Drop` impl should remove its own entry from `pending` instead of
+        // leaving a dead one for the next waiter on the same key to get
+        // stuck behind.
+        drop(first);
+
+        let flag_b = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker_b = Waker::from(flag_b.clone());
+        let mut cx_b = Context::from_waker(&waker_b);
+        let mut second = Box::pin(pool.take("a"));
+        assert!(second.as_mut().poll(&mut cx_b).is_pending());
+
+        drop(guard);
+        assert!(
+            flag_b.0.load(Ordering::SeqCst),
+            "returning the object woke a dead entry left by an abandoned waiter instead of the live one"
+        );
+    }
+}
@@ -1,38 +1,171 @@
-use tokio::sync::Mutex;
 use std::collections::VecDeque;
 use std::future::Future;
 use std::task::{Context, Waker};
 use tokio::macros::support::{Pin, Poll};
 use std::mem;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crossbeam_queue::SegQueue;
+use futures_core::Stream;
+use tokio::time::Sleep;
+
+mod keyed;
+pub use keyed::{KeyedPool, KeyedPoolFuture, KeyedPoolGuard};
+
+type Recycler<T> = Box<dyn FnMut(&mut T) + Send>;
+type Validator<T> = Box<dyn FnMut(&T) -> bool + Send>;
 
 struct PoolInner<T: 'static + Default + Send> {
-    objects: Vec<T>,
-    pending: VecDeque<Waker>,
+    objects: SegQueue<T>,
+    // A single atomic waker slot loses wakeups under contention: two tasks
+    // parked on an exhausted pool would overwrite each other's waker and
+    // one would never wake up. A plain queue behind a short-lived std
+    // lock keeps every waiter, while the free list itself stays lock-free.
+    // Entries are removed by identity (`Waker::will_wake`) from
+    // `PoolFuture`'s `Drop` impl when a waiter gives up before resolving,
+    // so the queue doesn't accumulate dead entries behind it.
+    pending: Mutex<VecDeque<Waker>>,
+    recycle: Mutex<Option<Recycler<T>>>,
+    constructor: Mutex<Box<dyn FnMut() -> T + Send>>,
+    validator: Mutex<Option<Validator<T>>>,
+    max_size: usize,
+    live: AtomicUsize,
+}
+
+/// Pops objects off `inner.objects` until one passes the validator (or the
+/// queue runs dry), dropping and un-counting each one that fails so the
+/// caller never receives a dead object.
+fn pop_valid<T: 'static + Default + Send>(inner: &PoolInner<T>) -> Option<T> {
+    while let Some(content) = inner.objects.pop() {
+        let valid = inner
+            .validator
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_none_or(|validate| validate(&content));
+        if valid {
+            return Some(content);
+        }
+        inner.live.fetch_sub(1, Ordering::AcqRel);
+    }
+    None
 }
 
 pub struct Pool<T: 'static + Default + Send> {
-    inner: Arc<Mutex<PoolInner<T>>>,
+    inner: Arc<PoolInner<T>>,
 }
 
 pub struct PoolFuture<'a, T: 'static + Default + Send> {
-    in_pool: &'a Pool<T>
+    in_pool: &'a Pool<T>,
+    /// The waker this future last pushed onto `PoolInner::pending`, if any,
+    /// kept so `Drop` can remove that exact entry if the future is
+    /// abandoned without ever getting polled to completion.
+    parked: Option<Waker>,
 }
 
 pub struct PoolGuard<'a, T: 'static + Default + Send> {
     content: T,
     in_pool: &'a Pool<T>,
+    /// Whether `content` was actually checked out of `in_pool`. `take_or`'s
+    /// caller-supplied fallback sets this to `false` so it never joins the
+    /// free list (and isn't counted against `max_size`) on drop.
+    pooled: bool,
+}
+
+impl<'a, T: 'static + Default + Send> std::ops::Deref for PoolGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.content
+    }
+}
+
+impl<'a, T: 'static + Default + Send> std::ops::DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.content
+    }
+}
+
+/// A `Stream` of checked-out guards, driven by the same acquisition logic
+/// as [`PoolFuture`]. It never ends: polling it after the pool is drained
+/// simply parks the caller until an object becomes available again.
+pub struct PoolStream<'a, T: 'static + Default + Send> {
+    in_pool: &'a Pool<T>,
+    future: PoolFuture<'a, T>,
+}
+
+impl<'a, T: 'static + Default + Send> Stream for PoolStream<'a, T> {
+    type Item = PoolGuard<'a, T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.future).poll(cx) {
+            // A PoolFuture only ever resolves once, so swap in a fresh one
+            // to park for the next item.
+            Poll::Ready(guard) => {
+                this.future = PoolFuture {
+                    in_pool: this.in_pool,
+                    parked: None,
+                };
+                Poll::Ready(Some(guard))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The future returned by [`Pool::take_timeout`]. Resolves to `None` once
+/// `deadline` elapses; when the `TakeTimeout` is then dropped (as `.await`
+/// does right after it resolves), `future`'s own `Drop` impl removes its
+/// waker from [`PoolInner::pending`], so a timed-out waiter doesn't linger
+/// there.
+pub struct TakeTimeout<'a, T: 'static + Default + Send> {
+    future: PoolFuture<'a, T>,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<'a, T: 'static + Default + Send> Future for TakeTimeout<'a, T> {
+    type Output = Option<PoolGuard<'a, T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(guard) = Pin::new(&mut self.future).poll(cx) {
+            return Poll::Ready(Some(guard));
+        }
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
 }
 
 impl<'a, T: 'static + Default + Send> Drop for PoolGuard<'a, T> {
     fn drop(&mut self) {
-        let content = mem::take(&mut self.content);
-        let inner = self.in_pool.inner.clone();
-        tokio::spawn(async move {
-            let mut inner = inner.lock().await;
-            inner.objects.push(content);
-            inner.pending.pop_front().map(|it| it.wake());
-        });
+        if !self.pooled {
+            return;
+        }
+        let mut content = mem::take(&mut self.content);
+        let inner = &self.in_pool.inner;
+        if let Some(recycle) = inner.recycle.lock().unwrap().as_mut() {
+            recycle(&mut content);
+        }
+        inner.objects.push(content);
+        if let Some(waker) = inner.pending.lock().unwrap().pop_front() {
+            waker.wake();
+        }
+    }
+}
+
+impl<'a, T: 'static + Default + Send> Drop for PoolFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(waker) = self.parked.take() {
+            self.in_pool
+                .inner
+                .pending
+                .lock()
+                .unwrap()
+                .retain(|pending| !pending.will_wake(&waker));
+        }
     }
 }
 
@@ -40,53 +173,197 @@ impl<'a, T: 'static + Default + Send> Future for PoolFuture<'a, T> {
     type Output = PoolGuard<'a, T>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if let Ok(mut inner) = self.in_pool.inner.try_lock() {
-            if inner.objects.is_empty() {
-                inner.pending.push_back(cx.waker().clone());
-                Poll::Pending
-            } else {
-                Poll::Ready(PoolGuard {
-                    content: inner.objects.pop().unwrap(),
-                    in_pool: self.in_pool,
-                })
+        let this = self.get_mut();
+        let inner = &this.in_pool.inner;
+        if let Some(content) = pop_valid(inner) {
+            return Poll::Ready(PoolGuard {
+                content,
+                in_pool: this.in_pool,
+                pooled: true,
+            });
+        }
+        // Reserve a growth slot with a CAS loop rather than a plain
+        // load-then-fetch_add: two threads both observing `live < max_size`
+        // and then unconditionally incrementing would both construct,
+        // overshooting the cap the load was supposed to enforce.
+        loop {
+            let live = inner.live.load(Ordering::Acquire);
+            if live >= inner.max_size {
+                break;
+            }
+            if inner
+                .live
+                .compare_exchange_weak(live, live + 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                let content = (inner.constructor.lock().unwrap())();
+                return Poll::Ready(PoolGuard {
+                    content,
+                    in_pool: this.in_pool,
+                    pooled: true,
+                });
             }
-        } else {
-            Poll::Pending
         }
+        this.parked = Some(cx.waker().clone());
+        inner.pending.lock().unwrap().push_back(cx.waker().clone());
+        // An object may have been returned between the pop above and the
+        // register, in which case the waker that pushed it never saw us.
+        // Check again now that we're registered to avoid missing it.
+        if let Some(content) = pop_valid(inner) {
+            return Poll::Ready(PoolGuard {
+                content,
+                in_pool: this.in_pool,
+                pooled: true,
+            });
+        }
+        Poll::Pending
     }
 }
 
 impl<T: 'static + Default + Send> Pool<T> {
-    pub fn new(size: usize, mut constructor: impl FnMut() -> T) -> Self {
+    pub fn new(size: usize, mut constructor: impl FnMut() -> T + Send + 'static) -> Self {
+        let objects = SegQueue::new();
+        for _ in 0..size {
+            objects.push(constructor());
+        }
+        Self {
+            inner: Arc::new(PoolInner {
+                objects,
+                pending: Mutex::new(VecDeque::new()),
+                recycle: Mutex::new(None),
+                constructor: Mutex::new(Box::new(constructor)),
+                validator: Mutex::new(None),
+                max_size: size,
+                live: AtomicUsize::new(size),
+            }),
+        }
+    }
+
+    /// Registers `recycler` to run against each object right before it goes
+    /// back into the free list, so a `take` never hands out a buffer or
+    /// connection still holding the previous borrower's dirty state.
+    pub fn with_recycler(
+        size: usize,
+        mut constructor: impl FnMut() -> T + Send + 'static,
+        recycler: impl FnMut(&mut T) + Send + 'static,
+    ) -> Self {
+        let objects = SegQueue::new();
+        for _ in 0..size {
+            objects.push(constructor());
+        }
+        Self {
+            inner: Arc::new(PoolInner {
+                objects,
+                pending: Mutex::new(VecDeque::new()),
+                recycle: Mutex::new(Some(Box::new(recycler))),
+                constructor: Mutex::new(Box::new(constructor)),
+                validator: Mutex::new(None),
+                max_size: size,
+                live: AtomicUsize::new(size),
+            }),
+        }
+    }
+
+    /// Starts with `initial` objects and grows on demand up to `max`: when
+    /// `take`/`PoolFuture::poll` finds the free list empty and fewer than
+    /// `max` objects are live, it constructs a fresh one on the spot instead
+    /// of parking the caller, only blocking once `max` is reached.
+    pub fn bounded(
+        initial: usize,
+        max: usize,
+        mut constructor: impl FnMut() -> T + Send + 'static,
+    ) -> Self {
+        let objects = SegQueue::new();
+        for _ in 0..initial {
+            objects.push(constructor());
+        }
         Self {
-            inner: Arc::new(Mutex::new(PoolInner {
-                objects: (0..size).map(|_| constructor()).collect(),
-                pending: Default::default(),
-            })),
+            inner: Arc::new(PoolInner {
+                objects,
+                pending: Mutex::new(VecDeque::new()),
+                recycle: Mutex::new(None),
+                constructor: Mutex::new(Box::new(constructor)),
+                validator: Mutex::new(None),
+                max_size: max,
+                live: AtomicUsize::new(initial),
+            }),
+        }
+    }
+
+    /// Registers `validator` to run against each object popped from the
+    /// free list before it's handed out. Objects that fail are dropped and
+    /// un-counted rather than returned, so a stale connection or handle
+    /// never reaches a caller.
+    pub fn with_validator(
+        size: usize,
+        mut constructor: impl FnMut() -> T + Send + 'static,
+        validator: impl FnMut(&T) -> bool + Send + 'static,
+    ) -> Self {
+        let objects = SegQueue::new();
+        for _ in 0..size {
+            objects.push(constructor());
+        }
+        Self {
+            inner: Arc::new(PoolInner {
+                objects,
+                pending: Mutex::new(VecDeque::new()),
+                recycle: Mutex::new(None),
+                constructor: Mutex::new(Box::new(constructor)),
+                validator: Mutex::new(Some(Box::new(validator))),
+                max_size: size,
+                live: AtomicUsize::new(size),
+            }),
         }
     }
 
     pub fn take(&self) -> PoolFuture<T> {
         PoolFuture {
-            in_pool: self
+            in_pool: self,
+            parked: None,
+        }
+    }
+
+    /// Like [`Pool::take`], but gives up after `dur` instead of waiting
+    /// forever, resolving to `None` on timeout.
+    pub fn take_timeout(&self, dur: Duration) -> TakeTimeout<T> {
+        TakeTimeout {
+            future: self.take(),
+            deadline: Box::pin(tokio::time::sleep(dur)),
+        }
+    }
+
+    /// Exposes the pool as a `Stream` of guards, one per acquisition, so it
+    /// can be driven with combinators like `for_each_concurrent` or
+    /// `buffer_unordered` instead of manually looping on `take()`.
+    pub fn checkouts(&self) -> PoolStream<T> {
+        PoolStream {
+            in_pool: self,
+            future: PoolFuture {
+                in_pool: self,
+                parked: None,
+            },
         }
     }
 
     pub fn try_take(&self) -> Option<PoolGuard<T>> {
-        let mut inner = self.inner.try_lock().ok()?;
-        inner.objects.pop()
-            .map(|content| {
-                PoolGuard {
-                    content,
-                    in_pool: self,
-                }
-            })
+        pop_valid(&self.inner).map(|content| {
+            PoolGuard {
+                content,
+                in_pool: self,
+                pooled: true,
+            }
+        })
     }
 
+    /// Falls back to `value` when the pool is drained. The fallback never
+    /// joins the free list on drop: it didn't come from the pool, so
+    /// recycling and requeuing it would silently inflate the pool past
+    /// `max_size`.
     pub fn take_or(&self, value: T) -> PoolGuard<T> {
         self.try_take().unwrap_or_else(|| PoolGuard {
             content: value,
             in_pool: self,
+            pooled: false,
         })
     }
 
@@ -97,8 +374,246 @@ impl<T: 'static + Default + Send> Pool<T> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn concurrent_waiters_are_all_woken_on_return() {
+        use std::sync::atomic::AtomicBool;
+        use std::task::{Wake, Waker};
+
+        struct FlagWaker(AtomicBool);
+        impl Wake for FlagWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let pool = Pool::new(1, || 0);
+        let guard = pool.try_take().unwrap();
+
+        let mut first = Box::pin(pool.take());
+        let mut second = Box::pin(pool.take());
+        let flag1 = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let flag2 = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker1 = Waker::from(flag1.clone());
+        let waker2 = Waker::from(flag2.clone());
+        let mut cx1 = Context::from_waker(&waker1);
+        let mut cx2 = Context::from_waker(&waker2);
+
+        // Both tasks park on the drained, capped pool.
+        assert!(first.as_mut().poll(&mut cx1).is_pending());
+        assert!(second.as_mut().poll(&mut cx2).is_pending());
+
+        // Returning the sole object wakes one waiter, and returning it again
+        // wakes the other. With a single-slot AtomicWaker, registering the
+        // second waiter silently discards the first's registration, so the
+        // first is never woken and the second round of checks below fails.
+        drop(guard);
+        assert!(
+            flag1.0.load(Ordering::SeqCst) || flag2.0.load(Ordering::SeqCst),
+            "neither waiter was woken when the object was returned"
+        );
+        let woken_first = flag1.0.load(Ordering::SeqCst);
+        if woken_first {
+            assert!(first.as_mut().poll(&mut cx1).is_ready(), "first waiter timed out — lost wakeup");
+        } else {
+            assert!(second.as_mut().poll(&mut cx2).is_ready(), "second waiter timed out — lost wakeup");
+        }
+
+        // The winner's guard was dropped at the end of that statement,
+        // which must hand the object on to the other waiter.
+        if woken_first {
+            assert!(flag2.0.load(Ordering::SeqCst), "second waiter was never woken — lost wakeup");
+            assert!(second.as_mut().poll(&mut cx2).is_ready(), "second waiter timed out — lost wakeup");
+        } else {
+            assert!(flag1.0.load(Ordering::SeqCst), "first waiter was never woken — lost wakeup");
+            assert!(first.as_mut().poll(&mut cx1).is_ready(), "first waiter timed out — lost wakeup");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_objects_are_dropped_instead_of_handed_out() {
+        let pool = Pool::with_validator(1, || 1, |content: &i32| *content >= 0);
+        {
+            let mut guard = pool.take().await;
+            *guard = -1;
+        }
+        // The only object is now invalid; with `max_size` already reached,
+        // take() must construct a replacement rather than hand back -1.
+        let guard = pool.take().await;
+        assert_eq!(*guard, 1, "stale object was handed out instead of being dropped");
+    }
+
+    #[tokio::test]
+    async fn take_timeout_expires_when_pool_stays_drained() {
+        let pool = Pool::new(1, || 0);
+        let _guard = pool.take().await;
+        let result = pool.take_timeout(Duration::from_millis(20)).await;
+        assert!(result.is_none(), "take_timeout should have expired");
+    }
+
+    #[tokio::test]
+    async fn take_timeout_resolves_once_an_object_is_returned() {
+        let pool = Pool::new(1, || 0);
+        let guard = pool.take().await;
+        drop(guard);
+        let result = pool.take_timeout(Duration::from_millis(200)).await;
+        assert!(result.is_some(), "take_timeout should have found the returned object");
+    }
+
+    #[tokio::test]
+    async fn checkouts_stream_yields_a_guard_per_available_object() {
+        use futures_util::StreamExt;
+
+        let pool = Pool::new(2, || 0);
+        let mut checkouts = pool.checkouts();
+        let first = checkouts.next().await.unwrap();
+        let second = checkouts.next().await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), checkouts.next())
+                .await
+                .is_err(),
+            "stream yielded a guard from an empty pool"
+        );
+        drop(first);
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn bounded_pool_caps_growth_at_max_size() {
+        let pool = Pool::bounded(0, 1, || 0);
+        let _first = pool.take().await;
+        let second = tokio::time::timeout(Duration::from_millis(50), pool.take()).await;
+        assert!(second.is_err(), "pool grew a second object past max_size");
+    }
+
+    #[test]
+    fn bounded_growth_reservation_is_race_free() {
+        use std::sync::Barrier;
+        use std::task::Wake;
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let constructed = Arc::new(AtomicUsize::new(0));
+        let pool = Arc::new({
+            let constructed = constructed.clone();
+            Pool::bounded(0, 1, move || {
+                constructed.fetch_add(1, Ordering::SeqCst);
+                0
+            })
+        });
+        let barrier = Arc::new(Barrier::new(2));
+        // A second rendezvous keeps whichever guard gets constructed first
+        // alive until both threads have recorded their poll result, so a
+        // guard dropped (and its object recycled) mid-race can't let the
+        // other thread legitimately re-acquire it and mask the bug.
+        let done = Arc::new(Barrier::new(2));
+        let waker = Waker::from(Arc::new(NoopWaker));
+
+        // Two threads race to poll a future against the same drained,
+        // one-slot pool. A load-then-fetch_add growth check would let both
+        // observe `live < max_size` and both construct; the CAS loop must
+        // let only one through.
+        let results: Vec<bool> = (0..2)
+            .map(|_| {
+                let pool = pool.clone();
+                let barrier = barrier.clone();
+                let done = done.clone();
+                let waker = waker.clone();
+                std::thread::spawn(move || {
+                    let mut future = Box::pin(pool.take());
+                    let mut cx = Context::from_waker(&waker);
+                    barrier.wait();
+                    let poll_result = future.as_mut().poll(&mut cx);
+                    let ready = poll_result.is_ready();
+                    done.wait();
+                    ready
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert_eq!(
+            constructed.load(Ordering::SeqCst),
+            1,
+            "bounded pool grew past max_size under contention"
+        );
+        assert_eq!(
+            results.iter().filter(|ready| **ready).count(),
+            1,
+            "bounded pool handed out more guards than max_size allows"
+        );
+    }
+
+    #[test]
+    fn abandoning_a_waiter_does_not_block_the_next_one() {
+        use std::sync::atomic::AtomicBool;
+        use std::task::Wake;
+
+        struct FlagWaker(AtomicBool);
+        impl Wake for FlagWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let pool = Pool::new(1, || 0);
+        let guard = pool.try_take().unwrap();
+
+        let flag_a = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker_a = Waker::from(flag_a.clone());
+        let mut cx_a = Context::from_waker(&waker_a);
+        let mut first = Box::pin(pool.take());
+        assert!(first.as_mut().poll(&mut cx_a).is_pending());
+
+        // Abandon the first waiter before it resolves, the way
+        // `tokio::time::timeout` or a losing `select!` branch would. Its
+        // `Drop` impl should remove its own entry from `pending` instead of
+        // leaving a dead one for the next waiter to get stuck behind.
+        drop(first);
+
+        let flag_b = Arc::new(FlagWaker(AtomicBool::new(false)));
+        let waker_b = Waker::from(flag_b.clone());
+        let mut cx_b = Context::from_waker(&waker_b);
+        let mut second = Box::pin(pool.take());
+        assert!(second.as_mut().poll(&mut cx_b).is_pending());
+
+        drop(guard);
+        assert!(
+            flag_b.0.load(Ordering::SeqCst),
+            "returning the object woke a dead entry left by an abandoned waiter instead of the live one"
+        );
+    }
+
+    #[tokio::test]
+    async fn take_or_fallback_is_not_counted_against_the_pool() {
+        let pool = Pool::new(0, || 0);
+        assert!(pool.try_take().is_none());
+        drop(pool.take_or(42));
+        assert!(
+            pool.try_take().is_none(),
+            "take_or's caller-supplied fallback leaked into the free list"
+        );
+    }
+
+    #[tokio::test]
+    async fn recycler_runs_before_an_object_is_reused() {
+        let pool = Pool::with_recycler(1, || 0, |content: &mut i32| *content = 0);
+        {
+            let mut guard = pool.take().await;
+            *guard = 42;
+        }
+        let guard = pool.take().await;
+        assert_eq!(*guard, 0, "recycler did not run before the object was reused");
+    }
 }